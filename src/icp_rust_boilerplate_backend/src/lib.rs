@@ -3,9 +3,17 @@ extern crate serde;
 use candid::{Decode, Encode};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
-use std::{borrow::Cow, cell::RefCell};
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+};
 use ic_cdk::api::time;
 
+mod sha_random;
+use sha_random::ShaRandom;
+
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 type IdCell = Cell<u64, Memory>;
 
@@ -18,19 +26,97 @@ struct LotteryTicket {
     updated_at: Option<u64>,
 }
 
+// Version 0 of the ticket record: a `LotteryTicket` without `updated_at`.
+// Kept only so records tagged with that version can still be read back.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct LotteryTicketV0 {
+    id: u64,
+    owner: String,
+    numbers: Vec<u32>,
+    created_at: u64,
+}
+
+// Every on-disk shape `LotteryTicket` has ever had. `Storable` writes a
+// 1-byte version discriminant ahead of the Candid payload, and `from_bytes`
+// dispatches on it, upgrading older versions to the current shape with
+// sensible defaults for fields that didn't exist yet.
+enum LotteryTicketVersions {
+    V0(LotteryTicketV0),
+    Current(LotteryTicket),
+}
+
+const LOTTERY_TICKET_VERSION_V0: u8 = 0;
+const LOTTERY_TICKET_VERSION_CURRENT: u8 = 1;
+const LOTTERY_TICKET_MAX_SIZE: u32 = 1025;
+
+impl LotteryTicketVersions {
+    fn into_current(self) -> LotteryTicket {
+        match self {
+            LotteryTicketVersions::V0(v0) => LotteryTicket {
+                id: v0.id,
+                owner: v0.owner,
+                numbers: v0.numbers,
+                created_at: v0.created_at,
+                updated_at: None,
+            },
+            LotteryTicketVersions::Current(ticket) => ticket,
+        }
+    }
+}
+
+impl Storable for LotteryTicketVersions {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let (version, payload) = match self {
+            LotteryTicketVersions::V0(v0) => (LOTTERY_TICKET_VERSION_V0, Encode!(v0).unwrap()),
+            LotteryTicketVersions::Current(ticket) => (LOTTERY_TICKET_VERSION_CURRENT, Encode!(ticket).unwrap()),
+        };
+        let mut bytes = Vec::with_capacity(payload.len() + 1);
+        bytes.push(version);
+        bytes.extend_from_slice(&payload);
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let raw = bytes.as_ref();
+        let (version, payload) = raw.split_first().expect("empty LotteryTicket record");
+        let ticket = match *version {
+            LOTTERY_TICKET_VERSION_V0 => {
+                LotteryTicketVersions::V0(Decode!(payload, LotteryTicketV0).unwrap()).into_current()
+            }
+            LOTTERY_TICKET_VERSION_CURRENT => {
+                LotteryTicketVersions::Current(Decode!(payload, LotteryTicket).unwrap()).into_current()
+            }
+            _ => {
+                // Records written before schema versioning existed carry no
+                // discriminant byte at all -- the whole blob is a raw Candid
+                // `LotteryTicket` payload (`updated_at` included), so the
+                // leading byte here is actually the start of the Candid
+                // "DIDL" magic, not a version tag.
+                LotteryTicketVersions::Current(Decode!(raw, LotteryTicket).unwrap()).into_current()
+            }
+        };
+        LotteryTicketVersions::Current(ticket)
+    }
+}
+
+impl BoundedStorable for LotteryTicketVersions {
+    const MAX_SIZE: u32 = LOTTERY_TICKET_MAX_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
 // Implement Storable and BoundedStorable traits for LotteryTicket
 impl Storable for LotteryTicket {
     fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+        LotteryTicketVersions::Current(self.clone()).to_bytes()
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        LotteryTicketVersions::from_bytes(bytes).into_current()
     }
 }
 
 impl BoundedStorable for LotteryTicket {
-    const MAX_SIZE: u32 = 1024;  // Set an appropriate max size for your struct
+    const MAX_SIZE: u32 = LOTTERY_TICKET_MAX_SIZE;
     const IS_FIXED_SIZE: bool = false;
 }
 
@@ -39,9 +125,26 @@ struct LotteryDraw {
     id: u64,
     winning_numbers: Vec<u32>,
     draw_time: u64,
-    participants: Vec<String>,
+    participants: Vec<u64>,
+    winners: Vec<PrizeWinner>,
 }
 
+// A ticket that matched enough winning numbers to place in a prize tier.
+// `matches` is the tier: the number of winning numbers the ticket hit.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct PrizeWinner {
+    ticket_id: u64,
+    owner: String,
+    matches: u8,
+}
+
+// The lowest match count that still wins a prize.
+const MIN_PRIZE_MATCHES: u8 = 3;
+
+// How many records each spilled run holds when paginating lottery tickets;
+// tune to trade heap usage for the number of runs the final merge tracks.
+const TICKET_SORT_CHUNK_SIZE: usize = 100;
+
 // Implement Storable and BoundedStorable traits for LotteryDraw
 impl Storable for LotteryDraw {
     fn to_bytes(&self) -> Cow<[u8]> {
@@ -86,6 +189,16 @@ thread_local! {
     static LOTTERY_DRAW_STORAGE: RefCell<StableBTreeMap<u64, LotteryDraw, Memory>> = RefCell::new(
         StableBTreeMap::init(LOTTERY_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3))))
     );
+
+    // Scratch space for the external merge sort: keyed by `scratch_key(run_id,
+    // position)`, holding one sorted run per chunk spilled out of
+    // `LOTTERY_TICKET_STORAGE`.
+    static TICKET_SORT_SCRATCH: RefCell<StableBTreeMap<u64, LotteryTicket, Memory>> = RefCell::new(
+        StableBTreeMap::init(LOTTERY_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))))
+    );
+
+    // Lengths of the runs currently spilled in `TICKET_SORT_SCRATCH`.
+    static TICKET_SORT_RUN_LENGTHS: RefCell<Vec<u64>> = RefCell::new(Vec::new());
 }
 
 // Function to buy a lottery ticket
@@ -148,6 +261,7 @@ fn conduct_lottery_draw(winning_numbers: Vec<u32>) -> Result<LotteryDraw, Lotter
         winning_numbers,
         draw_time: time(),
         participants: Vec::new(),
+        winners: Vec::new(),
     };
 
     LOTTERY_DRAW_STORAGE.with(|m| m.borrow_mut().insert(id, draw.clone()));
@@ -176,12 +290,75 @@ fn participate_in_lottery_draw(ticket_id: u64, draw_id: u64) -> Result<LotteryDr
     })?;
 
     // Add the participant to the draw
-    draw.participants.push(ticket.owner.clone());
+    draw.participants.push(ticket.id);
 
     LOTTERY_DRAW_STORAGE.with(|m| m.borrow_mut().insert(draw_id, draw.clone()));
     Ok(draw)
 }
 
+// Function to resolve the winners of a lottery draw, bucketing participating
+// tickets into prize tiers by how many winning numbers they matched. Ties
+// within a tier are ordered using a SHA-256 stream seeded from the draw id
+// and draw time, so the outcome can be recomputed and verified by anyone.
+#[ic_cdk::update]
+fn resolve_draw_winners(draw_id: u64) -> Result<LotteryDraw, LotteryError> {
+    let mut draw = LOTTERY_DRAW_STORAGE.with(|service| {
+        service
+            .borrow()
+            .get(&draw_id)
+            .ok_or(LotteryError::NotFound {
+                msg: format!("Lottery draw with id={} not found", draw_id),
+            })
+    })?;
+
+    let mut tiers: HashMap<u8, Vec<(u64, String)>> = HashMap::new();
+    for ticket_id in &draw.participants {
+        let ticket = LOTTERY_TICKET_STORAGE.with(|service| service.borrow().get(ticket_id));
+        if let Some(ticket) = ticket {
+            let matches = ticket
+                .numbers
+                .iter()
+                .filter(|n| draw.winning_numbers.contains(n))
+                .count() as u8;
+            if matches >= MIN_PRIZE_MATCHES {
+                tiers
+                    .entry(matches)
+                    .or_default()
+                    .push((ticket.id, ticket.owner.clone()));
+            }
+        }
+    }
+
+    let mut rng = ShaRandom::new(format!("{}{}", draw_id, draw.draw_time));
+    let mut tier_keys: Vec<u8> = tiers.keys().cloned().collect();
+    tier_keys.sort_unstable_by(|a, b| b.cmp(a));
+
+    let mut winners = Vec::new();
+    for tier in tier_keys {
+        let mut remaining = tiers.remove(&tier).unwrap();
+        remaining.sort_by_key(|(ticket_id, _)| *ticket_id);
+
+        // Draw tied candidates out in a deterministic, verifiable order.
+        let mut ordered = Vec::with_capacity(remaining.len());
+        while !remaining.is_empty() {
+            let idx = rng.next_index(remaining.len());
+            ordered.push(remaining.remove(idx));
+        }
+
+        for (ticket_id, owner) in ordered {
+            winners.push(PrizeWinner {
+                ticket_id,
+                owner,
+                matches: tier,
+            });
+        }
+    }
+
+    draw.winners = winners;
+    LOTTERY_DRAW_STORAGE.with(|m| m.borrow_mut().insert(draw_id, draw.clone()));
+    Ok(draw)
+}
+
 // Function to get all lottery tickets
 #[ic_cdk::query]
 fn get_all_lottery_tickets() -> Result<Vec<LotteryTicket>, LotteryError> {
@@ -192,6 +369,143 @@ fn get_all_lottery_tickets() -> Result<Vec<LotteryTicket>, LotteryError> {
     Ok(tickets)
 }
 
+fn ticket_scratch_key(run_id: u32, position: u32) -> u64 {
+    ((run_id as u64) << 32) | position as u64
+}
+
+// One candidate value at the head of a spilled run of tickets, ordered by
+// `created_at` so a `BinaryHeap` can cheaply find the smallest head.
+struct TicketRunHead {
+    created_at: u64,
+    run_id: u32,
+    position: u32,
+    ticket: LotteryTicket,
+}
+
+impl PartialEq for TicketRunHead {
+    fn eq(&self, other: &Self) -> bool {
+        self.created_at == other.created_at
+    }
+}
+impl Eq for TicketRunHead {}
+impl PartialOrd for TicketRunHead {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for TicketRunHead {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.created_at.cmp(&other.created_at)
+    }
+}
+
+// Streams tickets out of stable storage in fixed-size chunks, sorts each
+// chunk by `created_at` in memory, and spills it into `TICKET_SORT_SCRATCH`
+// as a sorted run, so no more than one chunk needs to live on the heap at a
+// time. Returns the length of each run that was spilled.
+fn spill_sorted_ticket_runs() -> Vec<u64> {
+    TICKET_SORT_RUN_LENGTHS.with(|lengths| {
+        TICKET_SORT_SCRATCH.with(|scratch| {
+            let mut scratch_mut = scratch.borrow_mut();
+            for (run_id, len) in lengths.borrow().iter().enumerate() {
+                for position in 0..*len {
+                    scratch_mut.remove(&ticket_scratch_key(run_id as u32, position as u32));
+                }
+            }
+        });
+        lengths.borrow_mut().clear();
+    });
+
+    let mut run_lengths = Vec::new();
+    LOTTERY_TICKET_STORAGE.with(|tickets| {
+        let tickets_borrow = tickets.borrow();
+        let mut iter = tickets_borrow.iter();
+        let mut run_id = 0u32;
+        loop {
+            let mut chunk: Vec<LotteryTicket> = iter
+                .by_ref()
+                .take(TICKET_SORT_CHUNK_SIZE)
+                .map(|(_, v)| v)
+                .collect();
+            if chunk.is_empty() {
+                break;
+            }
+            chunk.sort_by_key(|ticket| ticket.created_at);
+
+            TICKET_SORT_SCRATCH.with(|scratch| {
+                let mut scratch_mut = scratch.borrow_mut();
+                for (position, ticket) in chunk.iter().enumerate() {
+                    scratch_mut.insert(ticket_scratch_key(run_id, position as u32), ticket.clone());
+                }
+            });
+            run_lengths.push(chunk.len() as u64);
+            run_id += 1;
+        }
+    });
+
+    TICKET_SORT_RUN_LENGTHS.with(|lengths| *lengths.borrow_mut() = run_lengths.clone());
+    run_lengths
+}
+
+// K-way merges the runs spilled by `spill_sorted_ticket_runs`, keeping only
+// one ticket per run in memory at a time, and returns just the requested
+// page of the fully merged order.
+fn merge_sorted_ticket_runs(run_lengths: &[u64], offset: u64, limit: u64) -> Vec<LotteryTicket> {
+    let mut heap: BinaryHeap<Reverse<TicketRunHead>> = BinaryHeap::new();
+    TICKET_SORT_SCRATCH.with(|scratch| {
+        let scratch_borrow = scratch.borrow();
+        for (run_id, len) in run_lengths.iter().enumerate() {
+            if *len == 0 {
+                continue;
+            }
+            if let Some(ticket) = scratch_borrow.get(&ticket_scratch_key(run_id as u32, 0)) {
+                heap.push(Reverse(TicketRunHead {
+                    created_at: ticket.created_at,
+                    run_id: run_id as u32,
+                    position: 0,
+                    ticket,
+                }));
+            }
+        }
+    });
+
+    let mut skipped = 0u64;
+    let mut page = Vec::new();
+    while let Some(Reverse(head)) = heap.pop() {
+        if skipped < offset {
+            skipped += 1;
+        } else if (page.len() as u64) < limit {
+            page.push(head.ticket.clone());
+        } else {
+            break;
+        }
+
+        let next_position = head.position + 1;
+        if (next_position as u64) < run_lengths[head.run_id as usize] {
+            TICKET_SORT_SCRATCH.with(|scratch| {
+                if let Some(ticket) = scratch.borrow().get(&ticket_scratch_key(head.run_id, next_position)) {
+                    heap.push(Reverse(TicketRunHead {
+                        created_at: ticket.created_at,
+                        run_id: head.run_id,
+                        position: next_position,
+                        ticket,
+                    }));
+                }
+            });
+        }
+    }
+
+    page
+}
+
+// Function to get one page of lottery tickets sorted by `created_at`,
+// without ever holding the whole ticket store in memory at once.
+#[ic_cdk::query]
+fn get_lottery_tickets_sorted_paginated(offset: u64, limit: u64) -> Result<Vec<LotteryTicket>, LotteryError> {
+    let run_lengths = spill_sorted_ticket_runs();
+    Ok(merge_sorted_ticket_runs(&run_lengths, offset, limit))
+}
+
 // Function to get all lottery draws
 #[ic_cdk::query]
 fn get_all_lottery_draws() -> Result<Vec<LotteryDraw>, LotteryError> {
@@ -202,5 +516,34 @@ fn get_all_lottery_draws() -> Result<Vec<LotteryDraw>, LotteryError> {
     Ok(draws)
 }
 
+// Lottery tickets and draws live directly in stable memory via the
+// `MemoryManager`, so there is nothing to snapshot or restore across an
+// upgrade; these hooks exist to make that guarantee explicit and auditable.
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {}
+
+// Function to rewrite every stored lottery ticket in the current schema
+// version, so no old-version records are left on disk once an upgrade adds
+// a field.
+#[ic_cdk::update]
+fn migrate_all() -> Result<u64, LotteryError> {
+    let migrated = LOTTERY_TICKET_STORAGE.with(|m| {
+        let keys: Vec<u64> = m.borrow().iter().map(|(k, _)| k).collect();
+        let mut storage = m.borrow_mut();
+        let mut count = 0u64;
+        for key in keys {
+            if let Some(ticket) = storage.get(&key) {
+                storage.insert(key, ticket);
+                count += 1;
+            }
+        }
+        count
+    });
+    Ok(migrated)
+}
+
 // Export the candid interface
 ic_cdk::export_candid!();
@@ -0,0 +1,61 @@
+// Deterministic pseudo-random helper used to resolve ties and draw winners
+// in a way that is fully reproducible and auditable from a seed, instead of
+// relying on any non-deterministic source.
+use sha2::{Digest, Sha256};
+
+pub struct ShaRandom {
+    seed: String,
+    counter: u64,
+}
+
+impl ShaRandom {
+    pub fn new(seed: String) -> Self {
+        Self { seed, counter: 0 }
+    }
+
+    // Returns the next value in the stream, reduced modulo `modulus`.
+    // Panics if `modulus` is zero, same as any other modulo-by-zero.
+    pub fn next_index(&mut self, modulus: usize) -> usize {
+        let mut hasher = Sha256::new();
+        hasher.update(self.seed.as_bytes());
+        hasher.update(self.counter.to_le_bytes());
+        let digest = hasher.finalize();
+        self.counter += 1;
+
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(&digest[..8]);
+        let value = u64::from_be_bytes(buf);
+        (value % modulus as u64) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_seed_produces_the_same_stream() {
+        let mut a = ShaRandom::new("draw-1".to_string());
+        let mut b = ShaRandom::new("draw-1".to_string());
+        let stream_a: Vec<usize> = (0..10).map(|_| a.next_index(100)).collect();
+        let stream_b: Vec<usize> = (0..10).map(|_| b.next_index(100)).collect();
+        assert_eq!(stream_a, stream_b);
+    }
+
+    #[test]
+    fn different_seeds_produce_different_streams() {
+        let mut a = ShaRandom::new("draw-1".to_string());
+        let mut b = ShaRandom::new("draw-2".to_string());
+        let stream_a: Vec<usize> = (0..10).map(|_| a.next_index(1_000_000)).collect();
+        let stream_b: Vec<usize> = (0..10).map(|_| b.next_index(1_000_000)).collect();
+        assert_ne!(stream_a, stream_b);
+    }
+
+    #[test]
+    fn indices_stay_within_modulus() {
+        let mut rng = ShaRandom::new("bounds-check".to_string());
+        for _ in 0..100 {
+            assert!(rng.next_index(7) < 7);
+        }
+    }
+}
@@ -5,7 +5,12 @@ use candid::{Decode, Encode};
 use ic_cdk::api::time;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
-use std::{borrow::Cow, cell::RefCell, collections::HashMap};
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, VecDeque},
+};
 // endregion --- IMPORTS
 
 // region: --- TYPES
@@ -19,8 +24,17 @@ type Result<T> = std::result::Result<T, Error>;
 enum Error {
     InsertFailed,
     VoteNotFoundError,
+    InvalidSeats,
+    ConstraintViolation,
+    DuplicateVote,
 }
 
+// How many recent (candidate, timestamp) entries to keep per voter.
+const VOTER_HISTORY_CAP: usize = 31;
+
+// How long, in nanoseconds, a voter must wait before casting another vote.
+const VOTE_LOCKOUT_WINDOW_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
         MemoryManager::init(DefaultMemoryImpl::default())
@@ -34,6 +48,31 @@ thread_local! {
     static VOTES: RefCell<StableBTreeMap<u64, Vote, Memory>> = RefCell::new(StableBTreeMap::init(
         MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
     ));
+
+    // Keyed by "category::group" so each group has a single min/max entry.
+    static CONSTRAINTS: RefCell<StableBTreeMap<String, Constraint, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
+    ));
+
+    // Keyed by candidate name; each candidate can belong to several groups.
+    static CANDIDATE_GROUPS: RefCell<StableBTreeMap<String, CandidateGroups, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
+    ));
+
+    // Keyed by voter; tracks recent voting activity to prevent replay/ballot-stuffing.
+    static VOTER_RECORDS: RefCell<StableBTreeMap<String, VoterRecord, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4)))
+    ));
+
+    // Scratch space for the external merge sort: keyed by `scratch_key(run_id,
+    // position)`, holding one sorted run per chunk spilled out of `VOTES`.
+    static SORT_SCRATCH: RefCell<StableBTreeMap<u64, Vote, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5)))
+    ));
+
+    // Lengths of the runs currently spilled in `SORT_SCRATCH`, so a later
+    // call can clear them before spilling a fresh set.
+    static SORT_RUN_LENGTHS: RefCell<Vec<u64>> = RefCell::new(Vec::new());
 }
 // endregion --- TYPES
 
@@ -43,10 +82,111 @@ struct Vote {
     candidate: String,
     voter: String,
     timestamp: u64,
+    preferences: Vec<String>,
+}
+
+// The pre-`preferences` shape `Vote` records were stored in. Kept only so
+// records written before that field existed can still be read back.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct VoteV0 {
+    id: u64,
+    candidate: String,
+    voter: String,
+    timestamp: u64,
+}
+
+// Every on-disk shape `Vote` has ever had. `Storable` writes a 1-byte
+// version discriminant ahead of the Candid payload, and `from_bytes`
+// dispatches on it, upgrading older versions to the current shape with
+// sensible defaults for fields that didn't exist yet.
+enum VoteVersions {
+    V0(VoteV0),
+    Current(Vote),
+}
+
+const VOTE_VERSION_V0: u8 = 0;
+const VOTE_VERSION_CURRENT: u8 = 1;
+// `preferences` is unbounded, so size this for a realistic worst-case ranked
+// ballot (dozens of candidate names) rather than a bare struct with no list.
+const VOTE_MAX_SIZE: u32 = 8192;
+
+impl VoteVersions {
+    fn into_current(self) -> Vote {
+        match self {
+            VoteVersions::V0(v0) => Vote {
+                id: v0.id,
+                candidate: v0.candidate,
+                voter: v0.voter,
+                timestamp: v0.timestamp,
+                preferences: Vec::new(),
+            },
+            VoteVersions::Current(vote) => vote,
+        }
+    }
+}
+
+impl Storable for VoteVersions {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let (version, payload) = match self {
+            VoteVersions::V0(v0) => (VOTE_VERSION_V0, Encode!(v0).unwrap()),
+            VoteVersions::Current(vote) => (VOTE_VERSION_CURRENT, Encode!(vote).unwrap()),
+        };
+        let mut bytes = Vec::with_capacity(payload.len() + 1);
+        bytes.push(version);
+        bytes.extend_from_slice(&payload);
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let raw = bytes.as_ref();
+        let (version, payload) = raw.split_first().expect("empty Vote record");
+        let vote = match *version {
+            VOTE_VERSION_V0 => VoteVersions::V0(Decode!(payload, VoteV0).unwrap()).into_current(),
+            VOTE_VERSION_CURRENT => VoteVersions::Current(Decode!(payload, Vote).unwrap()).into_current(),
+            _ => {
+                // Records written before schema versioning existed carry no
+                // discriminant byte at all -- the whole blob is a raw Candid
+                // `VoteV0` payload, so the leading byte here is actually the
+                // start of the Candid "DIDL" magic, not a version tag.
+                VoteVersions::V0(Decode!(raw, VoteV0).unwrap()).into_current()
+            }
+        };
+        VoteVersions::Current(vote)
+    }
+}
+
+impl BoundedStorable for VoteVersions {
+    const MAX_SIZE: u32 = VOTE_MAX_SIZE;
+    const IS_FIXED_SIZE: bool = false;
 }
 
 // region: --- IMPL
 impl Storable for Vote {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        VoteVersions::Current(self.clone()).to_bytes()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        VoteVersions::from_bytes(bytes).into_current()
+    }
+}
+
+impl BoundedStorable for Vote {
+    const MAX_SIZE: u32 = VOTE_MAX_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// A representation rule for one group within a category, e.g. "at least 2,
+// at most 4 winners from group A of category Region".
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Constraint {
+    category: String,
+    group: String,
+    min: u32,
+    max: u32,
+}
+
+impl Storable for Constraint {
     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
@@ -56,16 +196,66 @@ impl Storable for Vote {
     }
 }
 
-impl BoundedStorable for Vote {
+impl BoundedStorable for Constraint {
     const MAX_SIZE: u32 = 1024;
     const IS_FIXED_SIZE: bool = false;
 }
+
+// The set of "category::group" memberships a candidate belongs to.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct CandidateGroups {
+    memberships: Vec<String>,
+}
+
+impl Storable for CandidateGroups {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for CandidateGroups {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// A voter's recent activity: a bounded history of (candidate, timestamp)
+// entries plus an accumulated credit count, used to detect and block replay
+// votes.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct VoterRecord {
+    history: VecDeque<(String, u64)>,
+    credits: u64,
+}
+
+impl Storable for VoterRecord {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for VoterRecord {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
 // endregion --- IMPL
 
 // region: --- METHODS
 // Function to add new vote
 #[ic_cdk::update]
-fn add_vote(candidate: String, voter: String) -> Result<Vote> {
+fn add_vote(candidate: String, voter: String, preferences: Vec<String>) -> Result<Vote> {
+    let timestamp = time();
+    if voter_is_locked_out(&voter, timestamp) {
+        return Err(Error::DuplicateVote);
+    }
+
     let id = ID_COUNTER
         .with(|counter| {
             let current_value = *counter.borrow().get();
@@ -74,23 +264,26 @@ fn add_vote(candidate: String, voter: String) -> Result<Vote> {
         .expect("cannot increment id counter");
     let vote = Vote {
         id,
-        candidate,
-        voter,
-        timestamp: time(),
+        candidate: candidate.clone(),
+        voter: voter.clone(),
+        timestamp,
+        preferences,
     };
     insert(&vote);
+    record_vote(voter, candidate, timestamp);
     Ok(vote)
 }
 
-// Function to update a vote by id - update candidate, voter
+// Function to update a vote by id - update candidate, voter, preferences
 #[ic_cdk::update]
-fn update_vote(id: u64, candidate: String, voter: String) -> Result<Vote> {
+fn update_vote(id: u64, candidate: String, voter: String, preferences: Vec<String>) -> Result<Vote> {
     let mut vote = VOTES
         .with(|votes| votes.borrow().get(&id))
         .ok_or(Error::VoteNotFoundError)?;
     vote.candidate = candidate.clone();
     vote.voter = voter.clone();
     vote.timestamp = time();
+    vote.preferences = preferences;
 
     insert(&vote);
     Ok(vote)
@@ -254,12 +447,638 @@ fn get_votes_sorted_by_timestamp() -> Result<Vec<Vote>> {
         Ok(votes_sorted)
     })
 }
+// Function to get a voter's accumulated credits
+#[ic_cdk::query]
+fn get_voter_credits(voter: String) -> Result<u64> {
+    VOTER_RECORDS.with(|records| {
+        Ok(records
+            .borrow()
+            .get(&voter)
+            .map(|record| record.credits)
+            .unwrap_or(0))
+    })
+}
+
+// Function to get a voter's recent (candidate, timestamp) history
+#[ic_cdk::query]
+fn get_voter_history(voter: String) -> Result<Vec<(String, u64)>> {
+    VOTER_RECORDS.with(|records| {
+        Ok(records
+            .borrow()
+            .get(&voter)
+            .map(|record| record.history.into_iter().collect())
+            .unwrap_or_default())
+    })
+}
 // endregion --- METHODS
 
+// region: --- CONSTRAINTS
+fn constraint_key(category: &str, group: &str) -> String {
+    format!("{}::{}", category, group)
+}
+
+// Function to add or update a representation constraint for a group
+#[ic_cdk::update]
+fn add_constraint(category: String, group: String, min: u32, max: u32) -> Result<Constraint> {
+    let constraint = Constraint {
+        category,
+        group,
+        min,
+        max,
+    };
+    let key = constraint_key(&constraint.category, &constraint.group);
+    CONSTRAINTS.with(|constraints| constraints.borrow_mut().insert(key, constraint.clone()));
+    Ok(constraint)
+}
+
+// Function to record which group(s) a candidate belongs to
+#[ic_cdk::update]
+fn assign_candidate_group(candidate: String, category: String, group: String) -> Result<()> {
+    let key = constraint_key(&category, &group);
+    CANDIDATE_GROUPS.with(|groups| {
+        let mut entry = groups.borrow().get(&candidate).unwrap_or_default();
+        if !entry.memberships.contains(&key) {
+            entry.memberships.push(key);
+        }
+        groups.borrow_mut().insert(candidate, entry);
+    });
+    Ok(())
+}
+
+// The "category::group" keys a candidate belongs to.
+fn candidate_memberships(candidate: &str) -> Vec<String> {
+    CANDIDATE_GROUPS.with(|groups| {
+        groups
+            .borrow()
+            .get(&candidate.to_string())
+            .map(|g| g.memberships)
+            .unwrap_or_default()
+    })
+}
+
+// How many already-elected candidates belong to this group.
+fn elected_in_group(elected: &[String], key: &str) -> u32 {
+    elected
+        .iter()
+        .filter(|candidate| candidate_memberships(candidate).iter().any(|m| m == key))
+        .count() as u32
+}
+
+// How many candidates still in the running (hopeful or elected) belong to this group.
+fn remaining_in_group(cards: &HashMap<String, CountCard>, key: &str) -> u32 {
+    cards
+        .iter()
+        .filter(|(candidate, card)| {
+            card.state != CandidateState::Excluded
+                && candidate_memberships(candidate).iter().any(|m| m == key)
+        })
+        .count() as u32
+}
+
+// A candidate may not be elected if doing so would push any of its groups
+// past their configured maximum.
+fn can_elect(candidate: &str, elected: &[String]) -> bool {
+    candidate_memberships(candidate).iter().all(|key| {
+        CONSTRAINTS.with(|constraints| constraints.borrow().get(key)).map_or(true, |constraint| {
+            elected_in_group(elected, key) + 1 <= constraint.max
+        })
+    })
+}
+
+// A candidate must be guarded from exclusion if removing it would make it
+// impossible for one of its groups to still meet its minimum.
+fn can_exclude(candidate: &str, cards: &HashMap<String, CountCard>) -> bool {
+    candidate_memberships(candidate).iter().all(|key| {
+        CONSTRAINTS.with(|constraints| constraints.borrow().get(key)).map_or(true, |constraint| {
+            remaining_in_group(cards, key) > constraint.min
+        })
+    })
+}
+// endregion --- CONSTRAINTS
+
+// region: --- STV ELECTION
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug, PartialEq)]
+enum CandidateState {
+    Hopeful,
+    Elected,
+    Excluded,
+}
+
+// Per-candidate tally state carried across STV rounds, mirroring the
+// "count card" used by manual ranked-choice counts.
+#[derive(Clone, Debug)]
+struct CountCard {
+    votes: f64,
+    state: CandidateState,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+enum StvAction {
+    Elected { candidate: String, tally: f64 },
+    Excluded { candidate: String, tally: f64 },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+struct StvRoundLog {
+    round: u32,
+    action: StvAction,
+    tallies: HashMap<String, f64>,
+}
+
+// The outcome of an STV election: the winners plus the round-by-round
+// breakdown that produced them. Returned directly from `run_stv_election`
+// rather than cached in heap state, since a query call's state changes are
+// never guaranteed to persist and a later query couldn't rely on reading it
+// back.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+struct StvElectionResult {
+    winners: Vec<String>,
+    rounds: Vec<StvRoundLog>,
+}
+
+// A single ranked ballot plus its current fractional transfer value.
+struct Ballot {
+    preferences: Vec<String>,
+    value: f64,
+}
+
+impl Ballot {
+    // The highest-ranked candidate on this ballot that is still in the running.
+    fn first_continuing<'a>(&'a self, cards: &HashMap<String, CountCard>) -> Option<&'a String> {
+        self.preferences.iter().find(|candidate| {
+            cards
+                .get(*candidate)
+                .map(|card| card.state == CandidateState::Hopeful)
+                .unwrap_or(false)
+        })
+    }
+}
+
+// Function to run a single-transferable-vote election over the recorded
+// votes, using each vote's `preferences` as its ranked ballot (falling back
+// to the single `candidate` field for ballots that have none). Applies any
+// group constraints registered via `add_constraint`/`assign_candidate_group`:
+// no candidate is elected past a group's max, and no candidate is excluded
+// if that would leave one of its groups unable to reach its min. Fails with
+// `Error::ConstraintViolation` when every hopeful is guarded that way or the
+// last hopefuls would breach a max. Group minimums are not checked when a
+// candidate is elected on quota, so the winners can fall short of a min.
+// Returns the winners together with the round-by-round audit log, since a
+// query call's heap-state writes aren't guaranteed to persist for a later
+// call to read back.
+#[ic_cdk::query]
+fn run_stv_election(seats: u32) -> Result<StvElectionResult> {
+    if seats == 0 {
+        return Err(Error::InvalidSeats);
+    }
+
+    let mut ballots: Vec<Ballot> = VOTES.with(|votes| {
+        votes
+            .borrow()
+            .iter()
+            .map(|(_, vote)| {
+                let preferences = if vote.preferences.is_empty() {
+                    vec![vote.candidate.clone()]
+                } else {
+                    vote.preferences.clone()
+                };
+                Ballot {
+                    preferences,
+                    value: 1.0,
+                }
+            })
+            .collect()
+    });
+
+    if ballots.is_empty() {
+        return Err(Error::VoteNotFoundError);
+    }
+
+    let valid_ballots = ballots.len() as u64;
+    let quota = (valid_ballots / (seats as u64 + 1)) + 1;
+
+    let mut cards: HashMap<String, CountCard> = HashMap::new();
+    for ballot in &ballots {
+        for candidate in &ballot.preferences {
+            cards.entry(candidate.clone()).or_insert(CountCard {
+                votes: 0.0,
+                state: CandidateState::Hopeful,
+            });
+        }
+    }
+
+    let mut elected: Vec<String> = Vec::new();
+    let mut rounds: Vec<StvRoundLog> = Vec::new();
+    let mut round = 0u32;
+
+    loop {
+        if elected.len() as u32 == seats {
+            break;
+        }
+
+        let continuing: Vec<String> = cards
+            .iter()
+            .filter(|(_, card)| card.state == CandidateState::Hopeful)
+            .map(|(candidate, _)| candidate.clone())
+            .collect();
+
+        // Once the remaining hopefuls no longer outnumber the remaining
+        // seats, elect them all without running another count.
+        if continuing.len() as u32 + (elected.len() as u32) <= seats {
+            let mut prospective = elected.clone();
+            for candidate in &continuing {
+                if !can_elect(candidate, &prospective) {
+                    return Err(Error::ConstraintViolation);
+                }
+                prospective.push(candidate.clone());
+            }
+            for candidate in continuing {
+                round += 1;
+                cards.get_mut(&candidate).unwrap().state = CandidateState::Elected;
+                rounds.push(StvRoundLog {
+                    round,
+                    action: StvAction::Elected {
+                        candidate: candidate.clone(),
+                        tally: cards[&candidate].votes,
+                    },
+                    tallies: cards.iter().map(|(k, v)| (k.clone(), v.votes)).collect(),
+                });
+                elected.push(candidate);
+            }
+            break;
+        }
+
+        round += 1;
+        for card in cards.values_mut() {
+            card.votes = 0.0;
+        }
+        for ballot in &ballots {
+            if let Some(candidate) = ballot.first_continuing(&cards) {
+                cards.get_mut(candidate).unwrap().votes += ballot.value;
+            }
+        }
+        // The candidate each ballot was tallied to this round, fixed before
+        // any state changes below.
+        let tallied_to: Vec<Option<String>> =
+            ballots.iter().map(|ballot| ballot.first_continuing(&cards).cloned()).collect();
+
+        let mut crossed_quota: Vec<String> = cards
+            .iter()
+            .filter(|(_, card)| card.state == CandidateState::Hopeful && card.votes >= quota as f64)
+            .map(|(candidate, _)| candidate.clone())
+            .collect();
+
+        crossed_quota.retain(|candidate| can_elect(candidate, &elected));
+
+        if !crossed_quota.is_empty() {
+            crossed_quota.sort_by(|a, b| cards[b].votes.partial_cmp(&cards[a].votes).unwrap());
+            for candidate in crossed_quota {
+                if elected.len() as u32 == seats {
+                    break;
+                }
+                if !can_elect(&candidate, &elected) {
+                    // Electing this candidate would now breach a group's max
+                    // because an earlier candidate in this round filled it.
+                    continue;
+                }
+                let tally = cards[&candidate].votes;
+                cards.get_mut(&candidate).unwrap().state = CandidateState::Elected;
+                elected.push(candidate.clone());
+
+                let surplus = tally - quota as f64;
+                if surplus > 0.0 {
+                    let transfer_value = surplus / tally;
+                    for (ballot, tallied) in ballots.iter_mut().zip(&tallied_to) {
+                        // Only ballots tallied to `candidate` this round carry its
+                        // surplus on to their next continuing preference.
+                        let contributed = tallied.as_ref() == Some(&candidate);
+                        if contributed {
+                            ballot.value *= transfer_value;
+                        }
+                    }
+                }
+
+                rounds.push(StvRoundLog {
+                    round,
+                    action: StvAction::Elected { candidate, tally },
+                    tallies: cards.iter().map(|(k, v)| (k.clone(), v.votes)).collect(),
+                });
+            }
+        } else {
+            let guardable: Vec<String> = cards
+                .iter()
+                .filter(|(candidate, card)| {
+                    card.state == CandidateState::Hopeful && can_exclude(candidate, &cards)
+                })
+                .map(|(candidate, _)| candidate.clone())
+                .collect();
+
+            let to_exclude = guardable
+                .iter()
+                .min_by(|a, b| cards[*a].votes.partial_cmp(&cards[*b].votes).unwrap())
+                .cloned();
+
+            match to_exclude {
+                Some(candidate) => {
+                    let tally = cards[&candidate].votes;
+                    cards.get_mut(&candidate).unwrap().state = CandidateState::Excluded;
+                    rounds.push(StvRoundLog {
+                        round,
+                        action: StvAction::Excluded { candidate, tally },
+                        tallies: cards.iter().map(|(k, v)| (k.clone(), v.votes)).collect(),
+                    });
+                }
+                None => {
+                    let any_hopeful = cards
+                        .values()
+                        .any(|card| card.state == CandidateState::Hopeful);
+                    if any_hopeful {
+                        // Every remaining hopeful is guarded by a group minimum
+                        // that the requested seat count cannot satisfy.
+                        return Err(Error::ConstraintViolation);
+                    }
+                    break;
+                }
+            }
+        }
+    }
+
+    Ok(StvElectionResult {
+        winners: elected,
+        rounds,
+    })
+}
+// endregion --- STV ELECTION
+
+// region: --- EXTERNAL SORT
+// How many records each spilled run holds; tune to trade heap usage for the
+// number of runs the final merge has to track.
+const EXTERNAL_SORT_CHUNK_SIZE: usize = 100;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug, PartialEq)]
+enum SortKey {
+    Timestamp,
+    Id,
+}
+
+fn sort_value(vote: &Vote, sort_key: &SortKey) -> (u64, u64) {
+    match sort_key {
+        SortKey::Timestamp => (vote.timestamp, vote.id),
+        SortKey::Id => (vote.id, 0),
+    }
+}
+
+fn scratch_key(run_id: u32, position: u32) -> u64 {
+    ((run_id as u64) << 32) | position as u64
+}
+
+// One candidate value at the head of a spilled run, ordered by its sort key
+// so a `BinaryHeap` can cheaply find the smallest head across all runs.
+struct RunHead {
+    key: (u64, u64),
+    run_id: u32,
+    position: u32,
+    vote: Vote,
+}
+
+impl PartialEq for RunHead {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+impl Eq for RunHead {}
+impl PartialOrd for RunHead {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for RunHead {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.key.cmp(&other.key)
+    }
+}
+
+// Streams votes out of stable storage in fixed-size chunks, sorts each chunk
+// in memory, and spills it into `SORT_SCRATCH` as a sorted run, so no more
+// than one chunk of records needs to live on the heap at a time. Returns the
+// length of each run that was spilled.
+fn spill_sorted_runs(sort_key: &SortKey) -> Vec<u64> {
+    SORT_RUN_LENGTHS.with(|lengths| {
+        SORT_SCRATCH.with(|scratch| {
+            let mut scratch_mut = scratch.borrow_mut();
+            for (run_id, len) in lengths.borrow().iter().enumerate() {
+                for position in 0..*len {
+                    scratch_mut.remove(&scratch_key(run_id as u32, position as u32));
+                }
+            }
+        });
+        lengths.borrow_mut().clear();
+    });
+
+    let mut run_lengths = Vec::new();
+    VOTES.with(|votes| {
+        let votes_borrow = votes.borrow();
+        let mut iter = votes_borrow.iter();
+        let mut run_id = 0u32;
+        loop {
+            let mut chunk: Vec<Vote> = iter.by_ref().take(EXTERNAL_SORT_CHUNK_SIZE).map(|(_, v)| v).collect();
+            if chunk.is_empty() {
+                break;
+            }
+            chunk.sort_by(|a, b| sort_value(a, sort_key).cmp(&sort_value(b, sort_key)));
+
+            SORT_SCRATCH.with(|scratch| {
+                let mut scratch_mut = scratch.borrow_mut();
+                for (position, vote) in chunk.iter().enumerate() {
+                    scratch_mut.insert(scratch_key(run_id, position as u32), vote.clone());
+                }
+            });
+            run_lengths.push(chunk.len() as u64);
+            run_id += 1;
+        }
+    });
+
+    SORT_RUN_LENGTHS.with(|lengths| *lengths.borrow_mut() = run_lengths.clone());
+    run_lengths
+}
+
+// K-way merges the runs spilled by `spill_sorted_runs`, keeping only one
+// value per run (a small binary heap of run heads) in memory at a time, and
+// returns just the requested page of the fully merged order.
+fn merge_sorted_runs(run_lengths: &[u64], offset: u64, limit: u64, sort_key: &SortKey) -> Vec<Vote> {
+    let mut heap: BinaryHeap<Reverse<RunHead>> = BinaryHeap::new();
+    SORT_SCRATCH.with(|scratch| {
+        let scratch_borrow = scratch.borrow();
+        for (run_id, len) in run_lengths.iter().enumerate() {
+            if *len == 0 {
+                continue;
+            }
+            if let Some(vote) = scratch_borrow.get(&scratch_key(run_id as u32, 0)) {
+                heap.push(Reverse(RunHead {
+                    key: sort_value(&vote, sort_key),
+                    run_id: run_id as u32,
+                    position: 0,
+                    vote,
+                }));
+            }
+        }
+    });
+
+    let mut skipped = 0u64;
+    let mut page = Vec::new();
+    while let Some(Reverse(head)) = heap.pop() {
+        if skipped < offset {
+            skipped += 1;
+        } else if (page.len() as u64) < limit {
+            page.push(head.vote.clone());
+        } else {
+            break;
+        }
+
+        let next_position = head.position + 1;
+        if (next_position as u64) < run_lengths[head.run_id as usize] {
+            SORT_SCRATCH.with(|scratch| {
+                if let Some(vote) = scratch.borrow().get(&scratch_key(head.run_id, next_position)) {
+                    heap.push(Reverse(RunHead {
+                        key: sort_value(&vote, sort_key),
+                        run_id: head.run_id,
+                        position: next_position,
+                        vote,
+                    }));
+                }
+            });
+        }
+    }
+
+    page
+}
+
+// Function to get one page of votes sorted by `sort_key`, without ever
+// holding the whole vote store in memory at once.
+#[ic_cdk::query]
+fn get_votes_sorted_paginated(offset: u64, limit: u64, sort_key: SortKey) -> Result<Vec<Vote>> {
+    let run_lengths = spill_sorted_runs(&sort_key);
+    Ok(merge_sorted_runs(&run_lengths, offset, limit, &sort_key))
+}
+// endregion --- EXTERNAL SORT
+
 // region: --- HELPER FN
 fn insert(vote: &Vote) {
     VOTES.with(|votes| votes.borrow_mut().insert(vote.id, vote.clone()));
 }
+
+// Whether `voter` cast a vote within the lockout window ending at `now`.
+fn voter_is_locked_out(voter: &str, now: u64) -> bool {
+    VOTER_RECORDS.with(|records| {
+        records
+            .borrow()
+            .get(&voter.to_string())
+            .and_then(|record| record.history.back().cloned())
+            .map(|(_, last_timestamp)| now.saturating_sub(last_timestamp) < VOTE_LOCKOUT_WINDOW_NANOS)
+            .unwrap_or(false)
+    })
+}
+
+// Appends a (candidate, timestamp) entry to the voter's history, evicting
+// the oldest entry once the cap is reached, and bumps their credits.
+fn record_vote(voter: String, candidate: String, timestamp: u64) {
+    VOTER_RECORDS.with(|records| {
+        let mut records_mut = records.borrow_mut();
+        let mut record = records_mut.get(&voter).unwrap_or_default();
+        record.history.push_back((candidate, timestamp));
+        if record.history.len() > VOTER_HISTORY_CAP {
+            record.history.pop_front();
+        }
+        record.credits += 1;
+        records_mut.insert(voter, record);
+    });
+}
 // endregion --- HELPER FN
 
+// region: --- SCHEMA MIGRATION
+// Votes, constraints, candidate groups and voter records all live directly
+// in stable memory via the `MemoryManager`, so there is nothing to snapshot
+// or restore across an upgrade; these hooks exist to make that guarantee
+// explicit and auditable.
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {}
+
+// Function to rewrite every stored vote in the current schema version, so
+// no old-version records are left on disk once an upgrade adds a field.
+#[ic_cdk::update]
+fn migrate_all() -> Result<u64> {
+    let migrated = VOTES.with(|votes| {
+        let keys: Vec<u64> = votes.borrow().iter().map(|(k, _)| k).collect();
+        let mut votes_mut = votes.borrow_mut();
+        let mut count = 0u64;
+        for key in keys {
+            if let Some(vote) = votes_mut.get(&key) {
+                votes_mut.insert(key, vote);
+                count += 1;
+            }
+        }
+        count
+    });
+    Ok(migrated)
+}
+// endregion --- SCHEMA MIGRATION
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Replaces the contents of `VOTES` with the given (id, timestamp) pairs.
+    fn seed_votes(entries: &[(u64, u64)]) {
+        VOTES.with(|votes| {
+            let mut votes_mut = votes.borrow_mut();
+            let keys: Vec<u64> = votes_mut.iter().map(|(k, _)| k).collect();
+            for key in keys {
+                votes_mut.remove(&key);
+            }
+            for (id, timestamp) in entries {
+                votes_mut.insert(
+                    *id,
+                    Vote {
+                        id: *id,
+                        candidate: "candidate".to_string(),
+                        voter: format!("voter-{}", id),
+                        timestamp: *timestamp,
+                        preferences: Vec::new(),
+                    },
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn merge_sort_orders_across_chunk_boundaries() {
+        // More entries than EXTERNAL_SORT_CHUNK_SIZE so the merge has to
+        // combine several spilled runs, not just sort a single chunk.
+        let total = EXTERNAL_SORT_CHUNK_SIZE as u64 * 3;
+        let entries: Vec<(u64, u64)> = (0..total).map(|i| (i, total - i)).collect();
+        seed_votes(&entries);
+
+        let page = get_votes_sorted_paginated(0, total, SortKey::Timestamp).unwrap();
+        let timestamps: Vec<u64> = page.iter().map(|v| v.timestamp).collect();
+        let mut expected = timestamps.clone();
+        expected.sort_unstable();
+
+        assert_eq!(timestamps.len(), total as usize);
+        assert_eq!(timestamps, expected);
+    }
+
+    #[test]
+    fn merge_sort_paginates_past_the_offset() {
+        seed_votes(&[(1, 30), (2, 10), (3, 20)]);
+
+        let page = get_votes_sorted_paginated(1, 1, SortKey::Timestamp).unwrap();
+
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].timestamp, 20);
+    }
+}
+
 ic_cdk::export_candid!();
\ No newline at end of file